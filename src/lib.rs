@@ -1,60 +1,106 @@
-use std::fmt;
+use std::collections::{BTreeMap, BTreeSet};
 
 use nom::{
     branch::alt,
-    bytes::complete::is_not,
+    bytes::complete::{is_not, tag},
     character::complete::{char, multispace0},
     combinator::{eof, map, opt, value},
-    multi::separated_list1,
+    multi::{many0, separated_list0, separated_list1},
     sequence::{delimited, pair, preceded, terminated},
-    Finish, IResult,
+    Finish, IResult, Slice,
 };
+use nom_locate::LocatedSpan;
 use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
 
-#[derive(Debug, PartialEq)]
-struct Recipe<'a> {
-    base: &'a str,
-    instructions: Vec<Instruction<'a>>,
+pub type Span<'a> = LocatedSpan<&'a str>;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Recipe<'a> {
+    pub base: Vec<Fragment<'a>>,
+    pub instructions: Vec<Instruction<'a>>,
 }
 
-#[derive(Debug, PartialEq)]
-enum Instruction<'a> {
-    AddIngredients { recipe: Recipe<'a>, optional: bool },
-    Process(&'a str),
+#[derive(Debug, PartialEq, Clone)]
+pub enum Instruction<'a> {
+    AddIngredients {
+        recipe: RecipeRef<'a>,
+        optional: bool,
+    },
+    Process(Vec<Fragment<'a>>),
 }
 
-impl<'a> fmt::Display for Recipe<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.base)?;
-        for (i, instruction) in self.instructions.iter().enumerate() {
-            if i == 0 {
-                write!(f, "　に")?;
-            }
-            write!(f, "\n{}　をして", instruction)?;
-        }
-        write!(f, "\n完成！")
-    }
+/// What a `+` step adds: a recipe spelled out inline, or `+@name(args...)`
+/// pointing at a recipe defined elsewhere in the document (see
+/// [`parse_document`]). `args` is empty for a plain `+@name` reference.
+/// `load`/`load_all` always hand back recipes with every reference already
+/// inlined into `Inline`, so `Named` only ever appears transiently while a
+/// `Document` is being resolved.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RecipeRef<'a> {
+    Inline(Recipe<'a>),
+    Named { name: &'a str, args: Vec<&'a str> },
 }
 
-impl<'a> fmt::Display for Instruction<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Instruction::AddIngredients { recipe, optional } => {
-                if *optional {
-                    write!(f, "お好みで　")?;
-                }
-                write!(f, "「{}　を", recipe.base)?;
-                for i in &recipe.instructions {
-                    write!(f, "　{}　して", i)?;
-                }
-                write!(f, "加える」")
+/// A named recipe definition: its optional ordered parameter list (bound to
+/// `{{param}}` placeholders in its body at each `+@name(...)` call site) and
+/// its body.
+#[derive(Debug, PartialEq, Clone)]
+struct NamedRecipe<'a> {
+    params: Vec<&'a str>,
+    recipe: Recipe<'a>,
+}
+
+/// A piece of ingredient/process text: literal characters, or a `{{name}}`
+/// placeholder to be filled in from the document's variable assignments.
+/// `span` pins down the placeholder's location for the undefined-variable
+/// error in [`render_fragments`].
+#[derive(Debug, PartialEq, Clone)]
+pub enum Fragment<'a> {
+    Text(&'a str),
+    Variable { name: &'a str, span: Span<'a> },
+}
+
+/// Splits `input`'s text into a sequence of literal and `{{name}}` fragments.
+/// An unterminated `{{` is treated as literal text rather than an error.
+fn fragments(input: Span) -> Vec<Fragment> {
+    let mut result = Vec::new();
+    let mut rest = input;
+    loop {
+        let text = *rest.fragment();
+        let Some(start) = text.find("{{") else {
+            if !text.is_empty() {
+                result.push(Fragment::Text(text));
             }
-            Instruction::Process(s) => write!(f, "「{}」", s),
+            break;
+        };
+        if start > 0 {
+            result.push(Fragment::Text(&text[..start]));
         }
+        let after_open = rest.slice(start + 2..);
+        let Some(end) = after_open.fragment().find("}}") else {
+            result.push(Fragment::Text(&text[start..]));
+            break;
+        };
+        let name_span = after_open.slice(..end);
+        result.push(Fragment::Variable {
+            name: name_span.fragment(),
+            span: name_span,
+        });
+        rest = after_open.slice(end + 2..);
     }
+    result
 }
 
-fn recipe(input: &str) -> IResult<&str, Recipe> {
+/// Trims leading/trailing whitespace from a span while keeping its line and
+/// column tracking accurate for the remaining text.
+fn trim_span(span: Span) -> Span {
+    let text = *span.fragment();
+    let start = text.len() - text.trim_start().len();
+    let end = start + text[start..].trim_end().len();
+    span.slice(start..end)
+}
+
+fn recipe(input: Span) -> IResult<Span, Recipe> {
     map(
         pair(
             preceded(skip, is_not(">)\r\n#")),
@@ -64,13 +110,13 @@ fn recipe(input: &str) -> IResult<&str, Recipe> {
             )),
         ),
         |(base, instructions)| Recipe {
-            base: base.trim(),
+            base: fragments(trim_span(base)),
             instructions: instructions.unwrap_or_default(),
         },
     )(input)
 }
 
-fn instruction(input: &str) -> IResult<&str, Instruction> {
+fn instruction(input: Span) -> IResult<Span, Instruction> {
     alt((
         map(
             pair(
@@ -78,10 +124,25 @@ fn instruction(input: &str) -> IResult<&str, Instruction> {
                 preceded(
                     preceded(skip, char('+')),
                     alt((
-                        delimited(preceded(skip, char('(')), recipe, preceded(skip, char(')'))),
-                        map(preceded(skip, is_not(">\r\n#")), |s: &str| Recipe {
-                            base: s.trim(),
-                            instructions: Vec::new(),
+                        map(
+                            delimited(preceded(skip, char('(')), recipe, preceded(skip, char(')'))),
+                            RecipeRef::Inline,
+                        ),
+                        map(
+                            pair(
+                                preceded(preceded(skip, char('@')), identifier),
+                                opt(call_args),
+                            ),
+                            |(name, args)| RecipeRef::Named {
+                                name,
+                                args: args.unwrap_or_default(),
+                            },
+                        ),
+                        map(preceded(skip, is_not(">\r\n#")), |s: Span| {
+                            RecipeRef::Inline(Recipe {
+                                base: fragments(trim_span(s)),
+                                instructions: Vec::new(),
+                            })
                         }),
                     )),
                 ),
@@ -91,45 +152,573 @@ fn instruction(input: &str) -> IResult<&str, Instruction> {
                 optional: opt.is_some(),
             },
         ),
-        map(preceded(skip, is_not(">)\r\n#")), |s: &str| {
-            Instruction::Process(s.trim())
+        map(preceded(skip, is_not(">)\r\n#")), |s: Span| {
+            Instruction::Process(fragments(trim_span(s)))
         }),
     ))(input)
 }
 
-fn comment(input: &str) -> IResult<&str, &str> {
-    preceded(
-        char('#'),
-        map(opt(is_not("\r\n")), Option::unwrap_or_default),
+/// A bare name, as used on the left of `name := ...` / `name = ...` (with an
+/// optional `(params...)` list) and after `+@` (with an optional
+/// `(args...)` list). `{{name}}` placeholders are not parsed with this; see
+/// [`fragments`].
+fn identifier(input: Span<'_>) -> IResult<Span<'_>, &str> {
+    map(is_not(" \t\r\n>)(#:="), |s: Span| *s.fragment())(input)
+}
+
+/// One argument in a `+@name(...)` call: the run of characters up to the
+/// next top-level `,` or whitespace, honoring parentheses nested inside the
+/// argument itself (e.g. `+@name((a) (b))`).
+fn call_arg(input: Span<'_>) -> IResult<Span<'_>, &str> {
+    let text = *input.fragment();
+    let mut depth = 0usize;
+    let mut end = text.len();
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                end = i;
+                break;
+            }
+            ')' => depth -= 1,
+            c if depth == 0 && (c == ',' || c.is_whitespace()) => {
+                end = i;
+                break;
+            }
+            _ => {}
+        }
+    }
+    if end == 0 {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::TakeTill1,
+        )));
+    }
+    Ok((input.slice(end..), &text[..end]))
+}
+
+/// A `+@name(...)` call's parenthesized, comma/space separated argument
+/// list.
+fn call_args(input: Span<'_>) -> IResult<Span<'_>, Vec<&str>> {
+    let (input, _) = preceded(skip, char('('))(input)?;
+    let mut args = Vec::new();
+    let mut rest = input;
+    loop {
+        let (after_skip, _) = skip(rest)?;
+        rest = after_skip;
+        if let Ok((after_close, _)) = char::<Span, nom::error::Error<Span>>(')')(rest) {
+            rest = after_close;
+            break;
+        }
+        let (after_arg, arg) = call_arg(rest)?;
+        args.push(arg);
+        let (after_skip, _) = skip(after_arg)?;
+        let (after_comma, _) = opt(char(','))(after_skip)?;
+        rest = after_comma;
+    }
+    Ok((rest, args))
+}
+
+/// A named recipe's parenthesized, comma separated parameter list, e.g.
+/// `(具)` in `炒め物(具) := ...`.
+fn param_list(input: Span<'_>) -> IResult<Span<'_>, Vec<&str>> {
+    delimited(
+        preceded(skip, char('(')),
+        separated_list0(preceded(skip, char(',')), preceded(skip, identifier)),
+        preceded(skip, char(')')),
     )(input)
 }
 
-fn skip(input: &str) -> IResult<&str, ()> {
+fn comment(input: Span) -> IResult<Span, ()> {
+    preceded(char('#'), value((), opt(is_not("\r\n"))))(input)
+}
+
+fn skip(input: Span) -> IResult<Span, ()> {
     delimited(multispace0, value((), opt(comment)), multispace0)(input)
 }
 
-fn parse(input: &str) -> IResult<&str, Recipe> {
-    terminated(delimited(skip, recipe, skip), eof)(input)
+/// A top-level `name := base > step > ...` definition (optionally
+/// `name(params...) := ...`), collected into the symbol table returned by
+/// [`parse_document`].
+fn named_recipe(input: Span<'_>) -> IResult<Span<'_>, (&str, NamedRecipe<'_>)> {
+    map(
+        pair(
+            pair(preceded(skip, identifier), opt(param_list)),
+            preceded(preceded(skip, tag(":=")), recipe),
+        ),
+        |((name, params), recipe)| {
+            (
+                name,
+                NamedRecipe {
+                    params: params.unwrap_or_default(),
+                    recipe,
+                },
+            )
+        },
+    )(input)
+}
+
+/// A top-level `name = value` variable assignment, collected into the
+/// variable map returned by [`parse_document`].
+fn assignment(input: Span<'_>) -> IResult<Span<'_>, (&str, &str)> {
+    map(
+        pair(
+            preceded(skip, identifier),
+            preceded(preceded(skip, char('=')), preceded(skip, is_not(">)\r\n#"))),
+        ),
+        |(name, value): (&str, Span)| (name, value.fragment().trim()),
+    )(input)
+}
+
+enum TopLevelItem<'a> {
+    Recipe(&'a str, NamedRecipe<'a>),
+    Assignment(&'a str, &'a str),
+}
+
+fn top_level_item(input: Span) -> IResult<Span, TopLevelItem> {
+    alt((
+        map(named_recipe, |(name, recipe)| {
+            TopLevelItem::Recipe(name, recipe)
+        }),
+        map(assignment, |(name, value)| {
+            TopLevelItem::Assignment(name, value)
+        }),
+    ))(input)
+}
+
+/// The result of [`parse_document`]: the named-recipe symbol table, the
+/// variable assignment map, and the main recipe that `transpile` ultimately
+/// renders. References inside `main` (`+@name`, `{{name}}`) are left
+/// unresolved; see `resolve_recipe` and `render_fragments`.
+#[derive(Debug)]
+struct ParsedDocument<'a> {
+    recipes: BTreeMap<&'a str, NamedRecipe<'a>>,
+    vars: BTreeMap<&'a str, &'a str>,
+    main: Recipe<'a>,
+}
+
+/// Parses a whole document: zero or more named recipe definitions and
+/// variable assignments, followed by the main recipe.
+fn parse_document(input: Span) -> IResult<Span, ParsedDocument> {
+    map(
+        terminated(pair(many0(top_level_item), recipe), preceded(skip, eof)),
+        |(items, main)| {
+            let mut recipes = BTreeMap::new();
+            let mut vars = BTreeMap::new();
+            for item in items {
+                match item {
+                    TopLevelItem::Recipe(name, recipe) => {
+                        recipes.insert(name, recipe);
+                    }
+                    TopLevelItem::Assignment(name, value) => {
+                        vars.insert(name, value);
+                    }
+                }
+            }
+            ParsedDocument {
+                recipes,
+                vars,
+                main,
+            }
+        },
+    )(input)
+}
+
+/// Named recipes directly referenced (via `+@name`) from `recipe`, including
+/// ones nested inside inline sub-recipes.
+fn direct_references<'a>(recipe: &Recipe<'a>, references: &mut Vec<&'a str>) {
+    for instruction in &recipe.instructions {
+        if let Instruction::AddIngredients { recipe, .. } = instruction {
+            match recipe {
+                RecipeRef::Inline(recipe) => direct_references(recipe, references),
+                RecipeRef::Named { name, .. } => references.push(name),
+            }
+        }
+    }
+}
+
+/// Walks the named-recipe reference graph depth-first, failing on the first
+/// cycle found. `visited` holds nodes whose whole subtree has been expanded
+/// (so each recipe is only traversed once); `stack` holds the recipes on the
+/// current DFS path, in order, so a cycle can be reported as `a -> b -> a`.
+fn check_acyclic<'a>(
+    name: &'a str,
+    table: &BTreeMap<&'a str, NamedRecipe<'a>>,
+    visited: &mut BTreeSet<&'a str>,
+    stack: &mut Vec<&'a str>,
+) -> Result<(), String> {
+    if visited.contains(name) {
+        return Ok(());
+    }
+    if let Some(pos) = stack.iter().position(|&n| n == name) {
+        let mut cycle = stack[pos..].to_vec();
+        cycle.push(name);
+        return Err(format!("circular recipe reference: {}", cycle.join(" -> ")));
+    }
+    let Some(named) = table.get(name) else {
+        return Ok(());
+    };
+    stack.push(name);
+    let mut references = Vec::new();
+    direct_references(&named.recipe, &mut references);
+    for referenced in references {
+        check_acyclic(referenced, table, visited, stack)?;
+    }
+    stack.pop();
+    visited.insert(name);
+    Ok(())
+}
+
+/// Inlines every `+@name` reference in `recipe` by looking it up in `table`,
+/// recursively resolving references found inside the referenced recipe too.
+fn resolve_recipe<'a>(
+    recipe: &Recipe<'a>,
+    table: &BTreeMap<&'a str, NamedRecipe<'a>>,
+) -> Result<Recipe<'a>, String> {
+    let instructions = recipe
+        .instructions
+        .iter()
+        .map(|instruction| resolve_instruction(instruction, table))
+        .collect::<Result<_, _>>()?;
+    Ok(Recipe {
+        base: recipe.base.clone(),
+        instructions,
+    })
+}
+
+fn resolve_instruction<'a>(
+    instruction: &Instruction<'a>,
+    table: &BTreeMap<&'a str, NamedRecipe<'a>>,
+) -> Result<Instruction<'a>, String> {
+    match instruction {
+        Instruction::Process(fragments) => Ok(Instruction::Process(fragments.clone())),
+        Instruction::AddIngredients { recipe, optional } => {
+            let resolved = match recipe {
+                RecipeRef::Inline(recipe) => resolve_recipe(recipe, table)?,
+                RecipeRef::Named { name, args } => {
+                    let named = table
+                        .get(name)
+                        .ok_or_else(|| format!("undefined recipe reference: {name}"))?;
+                    if named.params.len() != args.len() {
+                        return Err(format!(
+                            "recipe {name} expects {} argument(s) but {} were given",
+                            named.params.len(),
+                            args.len()
+                        ));
+                    }
+                    let bindings: BTreeMap<&str, &str> = named
+                        .params
+                        .iter()
+                        .copied()
+                        .zip(args.iter().copied())
+                        .collect();
+                    let bound = substitute_params(&named.recipe, &bindings);
+                    resolve_recipe(&bound, table)?
+                }
+            };
+            Ok(Instruction::AddIngredients {
+                recipe: RecipeRef::Inline(resolved),
+                optional: *optional,
+            })
+        }
+    }
+}
+
+/// Binds `bindings` (parameter name -> argument text) into `recipe`'s
+/// `{{param}}` fragments ahead of inlining a `+@name(args...)` call.
+/// Fragments for variables not in `bindings` are left untouched for
+/// `render_fragments` to resolve later.
+fn substitute_params<'a>(recipe: &Recipe<'a>, bindings: &BTreeMap<&str, &'a str>) -> Recipe<'a> {
+    Recipe {
+        base: substitute_params_fragments(&recipe.base, bindings),
+        instructions: recipe
+            .instructions
+            .iter()
+            .map(|instruction| substitute_params_instruction(instruction, bindings))
+            .collect(),
+    }
+}
+
+fn substitute_params_instruction<'a>(
+    instruction: &Instruction<'a>,
+    bindings: &BTreeMap<&str, &'a str>,
+) -> Instruction<'a> {
+    match instruction {
+        Instruction::Process(fragments) => {
+            Instruction::Process(substitute_params_fragments(fragments, bindings))
+        }
+        Instruction::AddIngredients { recipe, optional } => Instruction::AddIngredients {
+            recipe: match recipe {
+                RecipeRef::Inline(recipe) => RecipeRef::Inline(substitute_params(recipe, bindings)),
+                RecipeRef::Named { name, args } => RecipeRef::Named {
+                    name,
+                    args: args
+                        .iter()
+                        .map(|arg| bindings.get(arg).copied().unwrap_or(*arg))
+                        .collect(),
+                },
+            },
+            optional: *optional,
+        },
+    }
+}
+
+fn substitute_params_fragments<'a>(
+    fragments: &[Fragment<'a>],
+    bindings: &BTreeMap<&str, &'a str>,
+) -> Vec<Fragment<'a>> {
+    fragments
+        .iter()
+        .map(|fragment| match fragment {
+            Fragment::Text(s) => Fragment::Text(s),
+            Fragment::Variable { name, span } => match bindings.get(name) {
+                Some(value) => Fragment::Text(value),
+                None => Fragment::Variable { name, span: *span },
+            },
+        })
+        .collect()
+}
+
+/// Writes `fragments` to `out`, substituting each `{{name}}` placeholder with
+/// its value from `vars`, erroring with the variable's name and location if
+/// it was never assigned.
+fn render_fragments(
+    fragments: &[Fragment],
+    vars: &BTreeMap<&str, &str>,
+    out: &mut String,
+) -> Result<(), String> {
+    for fragment in fragments {
+        match fragment {
+            Fragment::Text(s) => out.push_str(s),
+            Fragment::Variable { name, span } => {
+                let value = vars.get(name).ok_or_else(|| {
+                    format!(
+                        "undefined variable: {name} (line {}, column {})",
+                        span.location_line(),
+                        span.get_utf8_column()
+                    )
+                })?;
+                out.push_str(value);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn render_instruction(
+    instruction: &Instruction,
+    vars: &BTreeMap<&str, &str>,
+    out: &mut String,
+) -> Result<(), String> {
+    match instruction {
+        Instruction::AddIngredients { recipe, optional } => {
+            let recipe = match recipe {
+                RecipeRef::Inline(recipe) => recipe,
+                RecipeRef::Named { name, .. } => {
+                    unreachable!("recipe reference {name} was not resolved before rendering")
+                }
+            };
+            if *optional {
+                out.push_str("お好みで　");
+            }
+            out.push('「');
+            render_fragments(&recipe.base, vars, out)?;
+            out.push_str("　を");
+            for i in &recipe.instructions {
+                out.push('　');
+                render_instruction(i, vars, out)?;
+                out.push_str("　して");
+            }
+            out.push_str("加える」");
+        }
+        Instruction::Process(fragments) => {
+            out.push('「');
+            render_fragments(fragments, vars, out)?;
+            out.push('」');
+        }
+    }
+    Ok(())
+}
+
+/// A fully-resolved recipe document, ready for [`render`]: every `+@name`
+/// reference has already been inlined (see [`resolve_recipe`]), so only
+/// `{{variable}}` placeholders remain to be filled in from `vars`. Built by
+/// [`load`]/[`load_all`], which do the parsing, cycle-checking, and
+/// reference-resolution up front.
+pub struct Document<'a> {
+    pub recipe: Recipe<'a>,
+    vars: BTreeMap<&'a str, &'a str>,
 }
 
+/// Turns `table`/`vars`/`main` (the output of [`parse_document`]) into a
+/// [`Document`]: checks the named-recipe reference graph for cycles, then
+/// inlines every `+@name` call in `main`.
+fn resolve_document<'a>(
+    table: &BTreeMap<&'a str, NamedRecipe<'a>>,
+    vars: BTreeMap<&'a str, &'a str>,
+    main: &Recipe<'a>,
+) -> Result<Document<'a>, String> {
+    let mut visited = BTreeSet::new();
+    for &name in table.keys() {
+        check_acyclic(name, table, &mut visited, &mut Vec::new())?;
+    }
+    let recipe = resolve_recipe(main, table)?;
+    Ok(Document { recipe, vars })
+}
+
+/// Parses and resolves a single recipe document from `input`. This is the
+/// plain-Rust entry point for embedding the parser without the wasm
+/// toolchain; see [`load_all`] for a file containing several recipes and
+/// [`render`] for turning the result into text.
+pub fn load(input: &str) -> Result<Document<'_>, String> {
+    let (_, document) = parse_document(Span::new(input))
+        .finish()
+        .map_err(|err| format!("parse error: {err}"))?;
+    resolve_document(&document.recipes, document.vars, &document.main)
+}
+
+/// Splits `input` into the text of each recipe it contains — recipes are
+/// separated by one or more blank lines, or an explicit `---` header line —
+/// and [`load`]s each one independently. This is the cookbook-file entry
+/// point: one call transpiles a whole file of recipes at once.
+pub fn load_all(input: &str) -> Result<Vec<Document<'_>>, String> {
+    split_documents(input).into_iter().map(load).collect()
+}
+
+/// Splits `input` on blank lines and `---` header lines, returning the
+/// trimmed text of each non-empty chunk in between.
+fn split_documents(input: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut chunk_start = 0usize;
+    let mut offset = 0usize;
+    for line in input.split_inclusive('\n') {
+        let trimmed = line.trim();
+        let is_separator =
+            trimmed.is_empty() || (trimmed.len() >= 3 && trimmed.chars().all(|c| c == '-'));
+        if is_separator {
+            if offset > chunk_start {
+                chunks.push(&input[chunk_start..offset]);
+            }
+            chunk_start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    if chunk_start < input.len() {
+        chunks.push(&input[chunk_start..]);
+    }
+    chunks
+        .into_iter()
+        .map(str::trim)
+        .filter(|chunk| !chunk.is_empty())
+        .collect()
+}
+
+/// Renders a resolved `document` to the final output text, substituting
+/// `{{name}}` placeholders from its variable assignments.
+pub fn render(document: &Document) -> Result<String, String> {
+    render_recipe(&document.recipe, &document.vars)
+}
+
+/// Renders a fully-resolved `recipe` (see `resolve_recipe`) to the final
+/// output text, substituting `{{name}}` placeholders from `vars`.
+fn render_recipe(recipe: &Recipe, vars: &BTreeMap<&str, &str>) -> Result<String, String> {
+    let mut out = String::new();
+    render_fragments(&recipe.base, vars, &mut out)?;
+    for (i, instruction) in recipe.instructions.iter().enumerate() {
+        if i == 0 {
+            out.push_str("　に");
+        }
+        out.push('\n');
+        render_instruction(instruction, vars, &mut out)?;
+        out.push_str("　をして");
+    }
+    out.push_str("\n完成！");
+    Ok(out)
+}
+
+/// The offending token's length, measured from the point a parser gave up:
+/// the run of characters up to the next grammar delimiter or whitespace,
+/// or at least one character so the caret always underlines something.
+fn token_len(remaining: &str) -> usize {
+    remaining
+        .chars()
+        .take_while(|c| !c.is_whitespace() && !">)(#".contains(*c))
+        .count()
+        .max(1)
+        .min(remaining.chars().count().max(1))
+}
+
+/// A parse failure's location and a source snippet with the offending token
+/// underlined, e.g. `message: "parse error: ..."`,
+/// `snippet: "aaa>>bbb\n   ^"`. Pure and `nom_locate`/`std`-only so it can be unit tested directly,
+/// unlike [`render_parse_error`], which only wraps this into a `JsValue`.
+struct ParseErrorInfo {
+    message: String,
+    line: u32,
+    column: usize,
+    snippet: String,
+}
+
+fn describe_parse_error(source: &str, err: &nom::error::Error<Span>) -> ParseErrorInfo {
+    let line = err.input.location_line();
+    let column = err.input.get_utf8_column();
+    let line_text = source.lines().nth((line - 1) as usize).unwrap_or("");
+    let len = token_len(err.input.fragment());
+    let caret = format!("{}{}", " ".repeat(column - 1), "^".repeat(len));
+    let snippet = format!("{line_text}\n{caret}");
+    let message = format!("parse error: {err}");
+    ParseErrorInfo {
+        message,
+        line,
+        column,
+        snippet,
+    }
+}
+
+fn render_parse_error(source: &str, err: nom::error::Error<Span>) -> JsValue {
+    let info = describe_parse_error(source, &err);
+
+    let obj = js_sys::Object::new();
+    let set = |key: &str, value: JsValue| {
+        js_sys::Reflect::set(&obj, &JsValue::from_str(key), &value).unwrap();
+    };
+    set("message", JsValue::from_str(&info.message));
+    set("line", JsValue::from_f64(info.line as f64));
+    set("column", JsValue::from_f64(info.column as f64));
+    set("snippet", JsValue::from_str(&info.snippet));
+    obj.into()
+}
+
+/// Thin wasm wrapper over [`load`]/[`render`], adding the caret-underlined
+/// structured error `load`'s plain `String` parse errors can't carry across
+/// the JS boundary.
 #[wasm_bindgen]
 pub fn transpile(input: &str) -> Result<JsValue, JsValue> {
-    let (_, recipe) = parse(input)
+    let (_, document) = parse_document(Span::new(input))
         .finish()
-        .map_err(|err| format!("parse error: {err}"))?;
-    let s = recipe.to_string();
-    Ok(JsValue::from_str(&s))
+        .map_err(|err| render_parse_error(input, err))?;
+
+    let document = resolve_document(&document.recipes, document.vars, &document.main)
+        .map_err(|err| JsValue::from_str(&err))?;
+    let rendered = render(&document).map_err(|err| JsValue::from_str(&err))?;
+    Ok(JsValue::from_str(&rendered))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{parse, Instruction, Recipe};
+    use std::collections::BTreeMap;
+
+    use nom::Finish;
+
+    use super::{
+        describe_parse_error, load, load_all, parse_document, render, resolve_recipe, Fragment,
+        Instruction, NamedRecipe, Recipe, RecipeRef, Span,
+    };
 
     #[test]
     fn test() {
-        assert_eq!(
-            parse(
-                r#"  # コメント
+        let (rest, document) = parse_document(Span::new(
+            r#"  # コメント
 aaa > bbb # コメント
 > + (#
     ccc>ddd>+( eee )
@@ -137,40 +726,212 @@ aaa > bbb # コメント
 ) > ? + (
     fff>ggg
 )
-# コメント"#
-            ),
-            Ok((
-                "",
-                Recipe {
-                    base: "aaa",
-                    instructions: vec![
-                        Instruction::Process("bbb"),
-                        Instruction::AddIngredients {
-                            recipe: Recipe {
-                                base: "ccc",
-                                instructions: vec![
-                                    Instruction::Process("ddd"),
-                                    Instruction::AddIngredients {
-                                        recipe: Recipe {
-                                            base: "eee",
-                                            instructions: vec![]
-                                        },
-                                        optional: false
-                                    }
-                                ]
-                            },
-                            optional: false
+# コメント"#,
+        ))
+        .unwrap();
+        assert_eq!(*rest.fragment(), "");
+        assert_eq!(document.recipes, BTreeMap::new());
+        assert_eq!(document.vars, BTreeMap::new());
+        assert_eq!(
+            document.main,
+            Recipe {
+                base: vec![Fragment::Text("aaa")],
+                instructions: vec![
+                    Instruction::Process(vec![Fragment::Text("bbb")]),
+                    Instruction::AddIngredients {
+                        recipe: RecipeRef::Inline(Recipe {
+                            base: vec![Fragment::Text("ccc")],
+                            instructions: vec![
+                                Instruction::Process(vec![Fragment::Text("ddd")]),
+                                Instruction::AddIngredients {
+                                    recipe: RecipeRef::Inline(Recipe {
+                                        base: vec![Fragment::Text("eee")],
+                                        instructions: vec![]
+                                    }),
+                                    optional: false
+                                }
+                            ]
+                        }),
+                        optional: false
+                    },
+                    Instruction::AddIngredients {
+                        recipe: RecipeRef::Inline(Recipe {
+                            base: vec![Fragment::Text("fff")],
+                            instructions: vec![Instruction::Process(vec![Fragment::Text("ggg")])]
+                        }),
+                        optional: true
+                    }
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_named_recipe_reference() {
+        let (rest, document) = parse_document(Span::new(
+            "ソース := しょうゆ > 煮る\n唐揚げ > 揚げる > +@ソース",
+        ))
+        .unwrap();
+        assert_eq!(*rest.fragment(), "");
+        assert_eq!(
+            document.recipes.get("ソース"),
+            Some(&NamedRecipe {
+                params: vec![],
+                recipe: Recipe {
+                    base: vec![Fragment::Text("しょうゆ")],
+                    instructions: vec![Instruction::Process(vec![Fragment::Text("煮る")])],
+                },
+            })
+        );
+        assert_eq!(
+            document.main,
+            Recipe {
+                base: vec![Fragment::Text("唐揚げ")],
+                instructions: vec![
+                    Instruction::Process(vec![Fragment::Text("揚げる")]),
+                    Instruction::AddIngredients {
+                        recipe: RecipeRef::Named {
+                            name: "ソース",
+                            args: vec![],
                         },
-                        Instruction::AddIngredients {
-                            recipe: Recipe {
-                                base: "fff",
-                                instructions: vec![Instruction::Process("ggg")]
-                            },
-                            optional: true
-                        }
-                    ],
+                        optional: false,
+                    },
+                ],
+            }
+        );
+
+        let resolved = resolve_recipe(&document.main, &document.recipes).unwrap();
+        assert_eq!(
+            resolved,
+            Recipe {
+                base: vec![Fragment::Text("唐揚げ")],
+                instructions: vec![
+                    Instruction::Process(vec![Fragment::Text("揚げる")]),
+                    Instruction::AddIngredients {
+                        recipe: RecipeRef::Inline(Recipe {
+                            base: vec![Fragment::Text("しょうゆ")],
+                            instructions: vec![Instruction::Process(vec![Fragment::Text("煮る")])],
+                        }),
+                        optional: false,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_circular_recipe_reference() {
+        use std::collections::BTreeSet;
+
+        use super::check_acyclic;
+
+        let (rest, document) =
+            parse_document(Span::new("a := x > +@b\nb := y > +@a\nmain")).unwrap();
+        assert_eq!(*rest.fragment(), "");
+
+        let mut visited = BTreeSet::new();
+        let err = check_acyclic("a", &document.recipes, &mut visited, &mut Vec::new()).unwrap_err();
+        assert_eq!(err, "circular recipe reference: a -> b -> a");
+    }
+
+    #[test]
+    fn test_variable_interpolation() {
+        let (rest, document) =
+            parse_document(Span::new("塩 = 小さじ1\n唐揚げ > {{塩}}をふる > 揚げる")).unwrap();
+        assert_eq!(*rest.fragment(), "");
+        assert_eq!(document.vars.get("塩"), Some(&"小さじ1"));
+        match &document.main.instructions[0] {
+            Instruction::Process(fragments) => match &fragments[..] {
+                [Fragment::Variable { name, .. }, Fragment::Text(rest)] => {
+                    assert_eq!(*name, "塩");
+                    assert_eq!(*rest, "をふる");
                 }
-            ))
+                other => panic!("unexpected fragments: {other:?}"),
+            },
+            other => panic!("expected a Process instruction, got {other:?}"),
+        }
+
+        let resolved = resolve_recipe(&document.main, &BTreeMap::new()).unwrap();
+        let rendered = super::render_recipe(&resolved, &document.vars).unwrap();
+        assert!(rendered.contains("小さじ1をふる"));
+    }
+
+    #[test]
+    fn test_parameterized_recipe_call() {
+        let (rest, document) = parse_document(Span::new(
+            "炒め物(具) := {{具}} > 炒める\n唐揚げ > 揚げる > +@炒め物(鶏肉)",
+        ))
+        .unwrap();
+        assert_eq!(*rest.fragment(), "");
+        assert_eq!(document.recipes.get("炒め物").unwrap().params, vec!["具"]);
+
+        let resolved = resolve_recipe(&document.main, &document.recipes).unwrap();
+        let rendered = super::render_recipe(&resolved, &document.vars).unwrap();
+        assert!(rendered.contains("鶏肉"));
+    }
+
+    #[test]
+    fn test_parameterized_recipe_call_arity_mismatch() {
+        let (rest, document) = parse_document(Span::new(
+            "炒め物(具) := {{具}} > 炒める\n唐揚げ > 揚げる > +@炒め物(鶏肉, にんにく)",
+        ))
+        .unwrap();
+        assert_eq!(*rest.fragment(), "");
+
+        let err = resolve_recipe(&document.main, &document.recipes).unwrap_err();
+        assert_eq!(err, "recipe 炒め物 expects 1 argument(s) but 2 were given");
+    }
+
+    #[test]
+    fn test_parameterized_recipe_call_forwards_bound_argument() {
+        let document = load(
+            "炒め物(具) := {{具}} > 炒める\n弁当(具) := ご飯 > +@炒め物(具)\n昼食 > +@弁当(鶏肉)",
+        )
+        .unwrap();
+        let rendered = render(&document).unwrap();
+        assert!(rendered.contains("鶏肉"));
+        assert!(!rendered.contains("具"));
+    }
+
+    #[test]
+    fn test_load_and_render() {
+        let document = load("塩 = 小さじ1\n唐揚げ > {{塩}}をふる > 揚げる").unwrap();
+        let rendered = render(&document).unwrap();
+        assert!(rendered.contains("小さじ1をふる"));
+    }
+
+    #[test]
+    fn test_load_all_splits_on_blank_lines_and_headers() {
+        let documents = load_all("唐揚げ > 揚げる\n\n---\n\nハンバーグ > 焼く").unwrap();
+        assert_eq!(documents.len(), 2);
+        assert_eq!(
+            render(&documents[0]).unwrap(),
+            render(&load("唐揚げ > 揚げる").unwrap()).unwrap()
+        );
+        assert_eq!(
+            render(&documents[1]).unwrap(),
+            render(&load("ハンバーグ > 焼く").unwrap()).unwrap()
         );
     }
+
+    #[test]
+    fn test_describe_parse_error_empty_input() {
+        let err = parse_document(Span::new("")).finish().unwrap_err();
+        let info = describe_parse_error("", &err);
+        assert_eq!(info.line, 1);
+        assert_eq!(info.column, 1);
+        assert_eq!(info.snippet, "\n^");
+        assert!(info.message.starts_with("parse error: "));
+    }
+
+    #[test]
+    fn test_describe_parse_error_points_at_second_line() {
+        let source = "aaa\n>";
+        let err = parse_document(Span::new(source)).finish().unwrap_err();
+        let info = describe_parse_error(source, &err);
+        assert_eq!(info.line, 2);
+        assert_eq!(info.column, 1);
+        assert_eq!(info.snippet, ">\n^");
+        assert!(info.message.starts_with("parse error: "));
+    }
 }